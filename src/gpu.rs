@@ -3,6 +3,59 @@ use screen::Screen;
 
 const VIDEO_RAM_SIZE: usize = 0x2000;
 
+// LCD mode durations in machine cycles (1 M-cycle = 4 dots); OAM scan + pixel
+// transfer + H-Blank add up to the 114-cycle visible line also used for V-Blank.
+const OAM_SCAN_CYCLES: u32 = 20;
+const PIXEL_TRANSFER_CYCLES: u32 = 43;
+const H_BLANK_CYCLES: u32 = 51;
+const V_BLANK_LINE_CYCLES: u32 = 114;
+
+/// Selects the RGB triples the four DMG shade indices are mapped to when a
+/// pixel is written to the screen buffer. The shade index itself (0 = lightest
+/// .. 3 = darkest) is what the rest of the GPU renders; this only changes how
+/// that index is finally painted.
+#[derive(Debug, Clone, Copy)]
+pub enum ScreenPalette {
+    Grayscale,
+    Green,
+    HighContrast,
+}
+
+impl ScreenPalette {
+    fn colors(self) -> [(u8, u8, u8); 4] {
+        match self {
+            ScreenPalette::Grayscale => [(255, 255, 255), (192, 192, 192), (96, 96, 96), (0, 0, 0)],
+            ScreenPalette::Green => [(155, 188, 15), (139, 172, 15), (48, 98, 48), (15, 56, 15)],
+            ScreenPalette::HighContrast => [(255, 255, 255), (255, 255, 255), (0, 0, 0), (0, 0, 0)],
+        }
+    }
+}
+
+// A frozen snapshot of everything the GPU needs to resume rendering exactly
+// where it left off. Excludes `next_screen_buffer` (reused in place on
+// restore) and `screen_data_sender` (left untouched by `restore_state`).
+#[derive(Debug, Clone, Copy)]
+pub struct GpuSaveState {
+    video_ram: [u8; VIDEO_RAM_SIZE],
+    oam: [u8; GPU::OAM_SIZE],
+    bg_palette: u8,
+    obj_palette_0: u8,
+    obj_palette_1: u8,
+    lcd_control: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    win_y: u8,
+    win_x: u8,
+    ly: u8,
+    lyc: u8,
+    mode: u8,
+    mode_clock: u32,
+    window_line: u8,
+    screen_palette: [(u8, u8, u8); 4],
+    interrupt: u8,
+}
+
 pub struct GPU {
     next_screen_buffer: Vec<u8>,
     video_ram: [u8; VIDEO_RAM_SIZE],
@@ -18,15 +71,24 @@ pub struct GPU {
     win_y: u8,
     win_x: u8,
     ly: u8,
-    render_clock: u32,
+    lyc: u8,
+    mode: u8, // current LCD mode (0 = H-Blank, 1 = V-Blank, 2 = OAM scan, 3 = pixel transfer)
+    mode_clock: u32,
     screen_data_sender: mpsc::SyncSender<Vec<u8>>,
     pub interrupt: u8,
+    bg_color_ids: Vec<u8>, // raw (pre-palette) bg color id per pixel on the current line, for OBJ-to-BG priority
+    window_line: u8, // internal window scanline counter; only advances on lines the window actually draws
+    screen_palette: [(u8, u8, u8); 4], // shade index -> final RGB, see ScreenPalette
 }
 
 impl GPU {
     pub const OAM_SIZE: usize = 0xA0;
 
     pub fn new(screen_data_sender: mpsc::SyncSender<Vec<u8>>) -> Self {
+        Self::with_palette(screen_data_sender, ScreenPalette::Grayscale)
+    }
+
+    pub fn with_palette(screen_data_sender: mpsc::SyncSender<Vec<u8>>, palette: ScreenPalette) -> Self {
         Self {
             next_screen_buffer: vec![0_u8; (3 * Screen::WIDTH * Screen::HEIGHT) as usize],
             video_ram: [0_u8; VIDEO_RAM_SIZE],
@@ -42,12 +104,62 @@ impl GPU {
             win_y: 0,
             win_x: 0,
             ly: 0,
-            render_clock: 0,
+            lyc: 0,
+            mode: 2,
+            mode_clock: 0,
             screen_data_sender,
             interrupt: 0,
+            bg_color_ids: vec![0_u8; Screen::WIDTH as usize],
+            window_line: 0,
+            screen_palette: palette.colors(),
+        }
+    }
+
+    pub fn save_state(&self) -> GpuSaveState {
+        GpuSaveState {
+            video_ram: self.video_ram,
+            oam: self.oam,
+            bg_palette: self.bg_palette,
+            obj_palette_0: self.obj_palette_0,
+            obj_palette_1: self.obj_palette_1,
+            lcd_control: self.lcd_control,
+            stat: self.stat,
+            scy: self.scy,
+            scx: self.scx,
+            win_y: self.win_y,
+            win_x: self.win_x,
+            ly: self.ly,
+            lyc: self.lyc,
+            mode: self.mode,
+            mode_clock: self.mode_clock,
+            window_line: self.window_line,
+            screen_palette: self.screen_palette,
+            interrupt: self.interrupt,
         }
     }
 
+    pub fn restore_state(&mut self, state: &GpuSaveState) {
+        self.video_ram = state.video_ram;
+        self.oam = state.oam;
+        self.bg_palette = state.bg_palette;
+        self.bg_palette_map = build_palette_map(state.bg_palette);
+        self.obj_palette_0 = state.obj_palette_0;
+        self.obj_palette_1 = state.obj_palette_1;
+        self.lcd_control = state.lcd_control;
+        self.stat = state.stat;
+        self.scy = state.scy;
+        self.scx = state.scx;
+        self.win_y = state.win_y;
+        self.win_x = state.win_x;
+        self.ly = state.ly;
+        self.lyc = state.lyc;
+        self.mode = state.mode;
+        self.mode_clock = state.mode_clock;
+        self.window_line = state.window_line;
+        self.screen_palette = state.screen_palette;
+        self.interrupt = state.interrupt;
+    }
+
     pub fn run_cycle(&mut self, cycles: u8) {
         if !self.is_lcd_on() {
             return
@@ -83,7 +195,8 @@ impl GPU {
             0xFF42 => self.scy,
             0xFF43 => self.scx,
             0xFF44 => self.ly,
-            0xFF46 => unreachable!("DMA Address is write only"),
+            0xFF45 => self.lyc,
+            0xFF46 => unreachable!("DMA read handled in mmu.rs"),
             0xFF47 => self.bg_palette,
             0xFF48 => self.obj_palette_0,
             0xFF49 => self.obj_palette_1,
@@ -100,6 +213,7 @@ impl GPU {
             0xFF42 => self.scy = value,
             0xFF43 => self.scx = value,
             0xFF44 => (), // read only
+            0xFF45 => self.lyc = value,
             0xFF46 => unreachable!("DMA write handled in mmu.rs"),
             0xFF47 => {
                 self.bg_palette = value;
@@ -114,15 +228,17 @@ impl GPU {
     }
 
     fn process_cycles(&mut self, cycles: u32) -> u8 {
-        if self.render_clock + cycles >= 114 {
+        let mode_length = self.mode_length();
+        if self.mode_clock + cycles >= mode_length {
+            // Only the cycles needed to reach the boundary are consumed here;
+            // the remainder is fed back into the new mode on the next call.
             #[cfg_attr(feature="clippy", allow(cast_possible_truncation))]
-            let used_cycles = (self.render_clock + cycles - 114) as u8;
-            self.render_clock = 0;
-            self.increment_line();
-            self.render_background();
+            let used_cycles = (mode_length - self.mode_clock) as u8;
+            self.mode_clock = 0;
+            self.advance_mode();
             used_cycles
         } else {
-            self.render_clock += cycles;
+            self.mode_clock += cycles;
             #[cfg_attr(feature="clippy", allow(cast_possible_truncation))]
             let cycles_u8 = cycles as u8;
             cycles_u8
@@ -133,6 +249,59 @@ impl GPU {
         self.lcd_control & 0x80 > 0
     }
 
+    fn mode_length(&self) -> u32 {
+        match self.mode {
+            2 => OAM_SCAN_CYCLES,
+            3 => PIXEL_TRANSFER_CYCLES,
+            0 => H_BLANK_CYCLES,
+            _ => V_BLANK_LINE_CYCLES, // mode 1, one V-Blank line at a time
+        }
+    }
+
+    // Cycles Mode 2 (OAM scan) -> Mode 3 (pixel transfer) -> Mode 0 (H-Blank)
+    // per visible line, then Mode 1 (V-Blank) for lines 144-153.
+    fn advance_mode(&mut self) {
+        match self.mode {
+            2 => self.set_mode(3),
+            3 => {
+                self.render_background();
+                self.render_sprites();
+                self.set_mode(0);
+            },
+            0 => {
+                self.increment_line();
+                if self.ly >= 144 {
+                    self.set_mode(1);
+                } else {
+                    self.set_mode(2);
+                }
+            },
+            _ => { // mode 1
+                self.increment_line();
+                if self.ly == 0 {
+                    self.set_mode(2);
+                }
+            },
+        }
+    }
+
+    fn set_mode(&mut self, mode: u8) {
+        self.mode = mode;
+        self.stat = (self.stat & 0xFC) | mode;
+
+        let stat_source_bit = match mode {
+            0 => Some(0x08), // Mode 0 (H-Blank) STAT interrupt source
+            1 => Some(0x10), // Mode 1 (V-Blank) STAT interrupt source
+            2 => Some(0x20), // Mode 2 (OAM scan) STAT interrupt source
+            _ => None, // Mode 3 has no STAT interrupt source
+        };
+        if let Some(bit) = stat_source_bit {
+            if self.stat & bit > 0 {
+                self.interrupt |= 0x02; // Mark STAT interrupt
+            }
+        }
+    }
+
     fn increment_line(&mut self) {
         self.ly = (self.ly + 1) % 154;
         if self.ly >= 144 { // V-Blank
@@ -140,6 +309,20 @@ impl GPU {
                 self.interrupt |= 0x01; // Mark V-Blank interrupt
             }
             self.render_screen();
+        } else if self.ly == 0 {
+            self.window_line = 0; // new frame
+        }
+        self.update_coincidence_flag();
+    }
+
+    fn update_coincidence_flag(&mut self) {
+        if self.ly == self.lyc {
+            self.stat |= 0x04;
+            if self.stat & 0x40 > 0 {
+                self.interrupt |= 0x02; // Mark STAT interrupt (LYC=LY coincidence)
+            }
+        } else {
+            self.stat &= !0x04;
         }
     }
 
@@ -156,14 +339,35 @@ impl GPU {
         let bgy_tile = (u16::from(bgy) & 0xFF) >> 3;
         let bgy_pixel_in_tile = u16::from(bgy) & 0x07;
 
+        let window_enabled = self.window_enabled();
+        let window_tile_map_addr = self.window_tile_map_addr();
+        let window_start_x = i32::from(self.win_x) - 7;
+        let mut window_drawn_this_line = false;
+
         for x in 0 .. Screen::WIDTH {
-            let bgx = u32::from(self.scx) + x;
-            #[cfg_attr(feature="clippy", allow(cast_possible_truncation))]
-            let bgx_tile = ((bgx & 0xFF) >> 3) as u16;
-            #[cfg_attr(feature="clippy", allow(cast_possible_truncation))]
-            let bgx_pixel_in_tile = (bgx & 0x07) as u8;
+            #[cfg_attr(feature="clippy", allow(cast_possible_wrap))]
+            let use_window = window_enabled && x as i32 >= window_start_x;
+
+            let (tile_map_addr, tile_y_tile, tile_y_pixel_in_tile, tile_x_tile, tile_x_pixel_in_tile) = if use_window {
+                window_drawn_this_line = true;
+                #[cfg_attr(feature="clippy", allow(cast_possible_wrap, cast_sign_loss))]
+                let winx = (x as i32 - window_start_x) as u32;
+                let winy = u16::from(self.window_line);
+                #[cfg_attr(feature="clippy", allow(cast_possible_truncation))]
+                let winx_tile = (winx >> 3) as u16;
+                #[cfg_attr(feature="clippy", allow(cast_possible_truncation))]
+                let winx_pixel_in_tile = (winx & 0x07) as u8;
+                (window_tile_map_addr, winy >> 3, winy & 0x07, winx_tile, winx_pixel_in_tile)
+            } else {
+                let bgx = u32::from(self.scx) + x;
+                #[cfg_attr(feature="clippy", allow(cast_possible_truncation))]
+                let bgx_tile = ((bgx & 0xFF) >> 3) as u16;
+                #[cfg_attr(feature="clippy", allow(cast_possible_truncation))]
+                let bgx_pixel_in_tile = (bgx & 0x07) as u8;
+                (bg_tile_map_addr, bgy_tile, bgy_pixel_in_tile, bgx_tile, bgx_pixel_in_tile)
+            };
 
-            let tile_number_addr = bg_tile_map_addr + bgy_tile * 32 + bgx_tile;
+            let tile_number_addr = tile_map_addr + tile_y_tile * 32 + tile_x_tile;
             let tile_number: u8 = self.read_byte_video_ram(tile_number_addr);
 //            if log_out {
 //                println!("TILE_NUMBER_ADDR: 0x{:02X}", tile_number_addr);
@@ -181,9 +385,9 @@ impl GPU {
             };
             let tile_addr = bg_tile_data_addr + tile_addr_offset;
 
-            let tile_line_addr = tile_addr + bgy_pixel_in_tile * 2;
+            let tile_line_addr = tile_addr + tile_y_pixel_in_tile * 2;
             let (tile_line_data_1, tile_line_data_2) = (self.read_byte_video_ram(tile_line_addr), self.read_byte_video_ram(tile_line_addr + 1));
-            let pixel_in_line_mask = 1 << bgx_pixel_in_tile;
+            let pixel_in_line_mask = 1 << tile_x_pixel_in_tile;
             let pixel_data_1: u8 = if tile_line_data_1 & pixel_in_line_mask > 0 {
                 1
             } else {
@@ -197,6 +401,7 @@ impl GPU {
 
             let palette_color_id = pixel_data_1 | pixel_data_2;
             let color: u8 = self.bg_palette_map[palette_color_id as usize];
+            self.bg_color_ids[x as usize] = palette_color_id;
 
 //            if log_out {
 //                println!("TILE_ADDR: 0x{:02X}", tile_addr);
@@ -208,6 +413,127 @@ impl GPU {
 
             self.set_pixel_color_next_screen_buffer(x, color);
         }
+
+        if window_drawn_this_line {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+    }
+
+    fn window_enabled(&self) -> bool {
+        self.lcd_control & 0x20 > 0 && self.ly >= self.win_y
+    }
+
+    fn window_tile_map_addr(&self) -> u16 {
+        if self.lcd_control & 0x40 > 0 {
+            0x9C00
+        } else {
+            0x9800
+        }
+    }
+
+    fn sprites_enabled(&self) -> bool {
+        self.lcd_control & 0x02 > 0
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcd_control & 0x04 > 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn render_sprites(&mut self) {
+        if self.ly >= 144 || !self.sprites_enabled() {
+            return
+        }
+
+        let sprite_height = self.sprite_height();
+
+        // Hardware caps each scanline at the first 10 sprites encountered in
+        // OAM order; X only decides draw priority among those survivors.
+        let mut sprites_on_line: Vec<(u8, usize)> = Vec::new();
+        for sprite_index in 0 .. 40 {
+            if sprites_on_line.len() >= 10 {
+                break
+            }
+
+            let oam_base = sprite_index * 4;
+            let sprite_y = i16::from(self.oam[oam_base]) - 16;
+            let line_in_sprite = i16::from(self.ly) - sprite_y;
+            if line_in_sprite >= 0 && line_in_sprite < i16::from(sprite_height) {
+                sprites_on_line.push((self.oam[oam_base + 1], sprite_index));
+            }
+        }
+
+        sprites_on_line.sort_by_key(|&(x, _)| x);
+
+        let obj_palette_map_0 = build_palette_map(self.obj_palette_0);
+        let obj_palette_map_1 = build_palette_map(self.obj_palette_1);
+
+        // Draw lowest-X (highest priority) sprites last so they end up on top.
+        for &(_, sprite_index) in sprites_on_line.iter().rev() {
+            self.render_sprite(sprite_index, sprite_height, &obj_palette_map_0, &obj_palette_map_1);
+        }
+    }
+
+    fn render_sprite(&mut self, sprite_index: usize, sprite_height: u8, obj_palette_map_0: &[u8; 4], obj_palette_map_1: &[u8; 4]) {
+        let oam_base = sprite_index * 4;
+        let sprite_y = i16::from(self.oam[oam_base]) - 16;
+        let sprite_x = i16::from(self.oam[oam_base + 1]) - 8;
+        let attributes = self.oam[oam_base + 3];
+
+        let mut tile_number = self.oam[oam_base + 2];
+        if sprite_height == 16 {
+            tile_number &= 0xFE;
+        }
+
+        let y_flip = attributes & 0x40 > 0;
+        let x_flip = attributes & 0x20 > 0;
+        let use_palette_1 = attributes & 0x10 > 0;
+        let behind_bg = attributes & 0x80 > 0;
+
+        let line_in_sprite = i16::from(self.ly) - sprite_y;
+        let line_in_tile = if y_flip {
+            i16::from(sprite_height) - 1 - line_in_sprite
+        } else {
+            line_in_sprite
+        };
+
+        // Sprite tiles always use the 0x8000 unsigned addressing mode.
+        let tile_addr = 0x8000 + u16::from(tile_number) * 16;
+        #[cfg_attr(feature="clippy", allow(cast_sign_loss))]
+        let tile_line_addr = tile_addr + (line_in_tile as u16) * 2;
+        let (tile_line_data_1, tile_line_data_2) = (self.read_byte_video_ram(tile_line_addr), self.read_byte_video_ram(tile_line_addr + 1));
+
+        let palette_map = if use_palette_1 { obj_palette_map_1 } else { obj_palette_map_0 };
+
+        for col in 0 .. 8_i16 {
+            let screen_x = sprite_x + col;
+            if screen_x < 0 || screen_x >= Screen::WIDTH as i16 {
+                continue
+            }
+
+            #[cfg_attr(feature="clippy", allow(cast_sign_loss, cast_possible_truncation))]
+            let pixel_in_tile = if x_flip { 7 - col } else { col } as u8;
+            let pixel_in_line_mask = 1 << pixel_in_tile;
+            let pixel_data_1: u8 = if tile_line_data_1 & pixel_in_line_mask > 0 { 1 } else { 0 };
+            let pixel_data_2: u8 = if tile_line_data_2 & pixel_in_line_mask > 0 { 2 } else { 0 };
+            let color_id = pixel_data_1 | pixel_data_2;
+
+            if color_id == 0 {
+                continue // transparent
+            }
+
+            #[cfg_attr(feature="clippy", allow(cast_sign_loss))]
+            let screen_x = screen_x as u32;
+            if behind_bg && self.bg_color_ids[screen_x as usize] != 0 {
+                continue
+            }
+
+            let color = palette_map[color_id as usize];
+            self.set_pixel_color_next_screen_buffer(screen_x, color);
+        }
     }
 
     fn bg_tile_data_addr(&self) -> u16 {
@@ -226,11 +552,12 @@ impl GPU {
         }
     }
 
-    fn set_pixel_color_next_screen_buffer(&mut self, x_pixel: u32, color: u8) {
+    fn set_pixel_color_next_screen_buffer(&mut self, x_pixel: u32, shade: u8) {
+        let (r, g, b) = self.screen_palette[shade as usize];
         let base_addr = (u32::from(self.ly) * Screen::WIDTH + x_pixel) as usize * 3;
-        self.next_screen_buffer[base_addr] = color;
-        self.next_screen_buffer[base_addr + 1] = color;
-        self.next_screen_buffer[base_addr + 2] = color;
+        self.next_screen_buffer[base_addr] = r;
+        self.next_screen_buffer[base_addr + 1] = g;
+        self.next_screen_buffer[base_addr + 2] = b;
     }
 
     fn read_byte_video_ram(&self, addr: u16) -> u8 {
@@ -245,6 +572,9 @@ impl GPU {
     }
 }
 
+// Maps a DMG palette register to the shade index (0 = lightest .. 3 = darkest)
+// each of the four dot-data values should display as. The final RGB for a
+// shade index is resolved later by `GPU::screen_palette`.
 fn build_palette_map(palette_layout: u8) -> [u8; 4] {
     [
         color_from_dot_data(palette_layout & 0x11),
@@ -256,9 +586,9 @@ fn build_palette_map(palette_layout: u8) -> [u8; 4] {
 
 fn color_from_dot_data(dot_data: u8) -> u8 {
     match dot_data {
-        0x00 => 255,
-        0x01 => 192,
-        0x10 => 96,
-        _ => 0,
+        0x00 => 0,
+        0x01 => 1,
+        0x10 => 2,
+        _ => 3,
     }
 }
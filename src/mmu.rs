@@ -1,7 +1,7 @@
 use std::fs::File;
 use std::io::Read;
 use std::sync::mpsc;
-use gpu::GPU;
+use gpu::{GPU, GpuSaveState, ScreenPalette};
 use serial::Serial;
 
 // Gameboy only needs 0x2000 working RAM
@@ -11,16 +11,50 @@ use serial::Serial;
 const WRAM_SIZE: usize = 0x2000;
 const ZRAM_SIZE: usize = 0x80;
 
+// OAM DMA copies 160 bytes (the full sprite attribute table) one byte per
+// machine cycle, so a transfer started on 0xFF46 takes ~160 cycles to land.
+#[derive(Debug, Clone, Copy)]
+struct OamDma {
+    source: u8, // high byte of the source address (source address is source << 8)
+    remaining_cycles: u8,
+}
+
+impl OamDma {
+    fn new() -> Self {
+        Self { source: 0, remaining_cycles: 0 }
+    }
+
+    fn active(&self) -> bool {
+        self.remaining_cycles > 0
+    }
+}
+
+// A frozen snapshot of MMU-owned memory and the nested GPU state. Excludes
+// `rom` and the GPU's `mpsc` sender; `restore_state` leaves both as they are
+// in the live MMU.
+#[derive(Debug, Clone, Copy)]
+pub struct MmuSaveState {
+    wram: [u8; WRAM_SIZE],
+    zram: [u8; ZRAM_SIZE],
+    gpu: GpuSaveState,
+    oam_dma: OamDma,
+}
+
 pub struct MMU {
     rom: Vec<u8>,
     wram: [u8; WRAM_SIZE], // Working RAM
     zram: [u8; ZRAM_SIZE], // Zero page RAM
     gpu: GPU,
     serial: Serial,
+    oam_dma: OamDma,
 }
 
 impl MMU {
     pub fn new(cart_path: &str, screen_data_sender: mpsc::SyncSender<Vec<u8>>) -> Self {
+        Self::with_palette(cart_path, screen_data_sender, ScreenPalette::Grayscale)
+    }
+
+    pub fn with_palette(cart_path: &str, screen_data_sender: mpsc::SyncSender<Vec<u8>>, palette: ScreenPalette) -> Self {
         let mut cart_data: Vec<u8> = Vec::new();
         Self::load_cart(cart_path, &mut cart_data);
 
@@ -28,15 +62,51 @@ impl MMU {
             rom: cart_data,
             wram: [0_u8; WRAM_SIZE],
             zram: [0_u8; ZRAM_SIZE],
-            gpu: GPU::new(screen_data_sender),
+            gpu: GPU::with_palette(screen_data_sender, palette),
             serial: Serial::new(),
+            oam_dma: OamDma::new(),
         }
     }
 
+    pub fn save_state(&self) -> MmuSaveState {
+        MmuSaveState {
+            wram: self.wram,
+            zram: self.zram,
+            gpu: self.gpu.save_state(),
+            oam_dma: self.oam_dma,
+        }
+    }
+
+    pub fn restore_state(&mut self, state: &MmuSaveState) {
+        self.wram = state.wram;
+        self.zram = state.zram;
+        self.gpu.restore_state(&state.gpu);
+        self.oam_dma = state.oam_dma;
+    }
+
     pub fn run_cycle(&mut self, cpu_cycles: u8) {
+        self.step_oam_dma(cpu_cycles);
         self.gpu.run_cycle(cpu_cycles)
     }
 
+    fn start_oam_dma(&mut self, source: u8) {
+        self.oam_dma = OamDma { source, remaining_cycles: GPU::OAM_SIZE as u8 };
+    }
+
+    fn step_oam_dma(&mut self, cycles: u8) {
+        for _ in 0 .. cycles {
+            if !self.oam_dma.active() {
+                break
+            }
+
+            let byte_offset = GPU::OAM_SIZE as u8 - self.oam_dma.remaining_cycles;
+            let src_addr = (u16::from(self.oam_dma.source) << 8) + u16::from(byte_offset);
+            let value = self.read_byte(src_addr);
+            self.gpu.write_oam(0xFE00 + u16::from(byte_offset), value);
+            self.oam_dma.remaining_cycles -= 1;
+        }
+    }
+
     // http://marc.rawer.de/Gameboy/Docs/GBCPUman.pdf
     pub fn read_byte(&mut self, addr: u16) -> u8 {
         match addr {
@@ -52,6 +122,7 @@ impl MMU {
 //            0xFF0F => 0, // Interrupt flag
 //            0xFF10...0xFF26 => 0, // Sound control
 //            0xFF30...0xFF3F => 0, // Sound wave pattern RAM
+            0xFF46 => self.oam_dma.source, // OAM DMA source register readback
             0xFF40...0xFF4B => self.gpu.read_control(addr),
 //            0xFF4C...0xFF7F => panic!("MMU ERROR: Memory mapped I/O (read) (CGB only) not implemented"), // Memory mapped I/O CGB ONLY
             0xFF80...0xFFFF => self.zram[(addr & 0x7F) as usize], // Zero page RAM
@@ -78,6 +149,7 @@ impl MMU {
 //            0xFF0F => (), // Interrupt flag
 //            0xFF10...0xFF26 => (), // Sound control
 //            0xFF30...0xFF3F => (), // Sound wave pattern RAM
+            0xFF46 => self.start_oam_dma(value), // OAM DMA transfer
             0xFF40...0xFF4B => self.gpu.write_control(addr, value),
 //            0xFF4C...0xFF7F => panic!("MMU ERROR: Memory mapped I/O (write) (CGB only) not implemented"), // Memory mapped I/O CGB ONLY
             0xFF80...0xFFFF => self.zram[(addr & 0x7F) as usize] = value, // Zero page RAM